@@ -1,27 +1,19 @@
+use std::collections::HashMap;
+use std::io::Read as _;
+
 use crate::crypt::cipher::Cipher;
 use crate::result::{DatabaseIntegrityError, Error, Result};
 
+use flate2::read::GzDecoder;
 use secstr::SecStr;
 
-use xml::name::OwnedName;
-use xml::reader::{EventReader, XmlEvent};
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::name::QName;
+use quick_xml::reader::NsReader;
+use quick_xml::writer::Writer;
 
 use super::db::{AutoType, AutoTypeAssociation, Entry, Group, Value, Metadata, Icon};
 
-#[derive(Debug)]
-enum Node {
-    Metadata(Metadata),
-    Entry(Entry),
-    Group(Group),
-    KeyValue(String, Value),
-    AutoType(AutoType),
-    AutoTypeAssociation(AutoTypeAssociation),
-    ExpiryTime(String),
-    Expires(bool),
-    Icon(Icon),
-    CustomIcon(String, String),
-}
-
 fn parse_xml_timestamp(t: &str) -> Result<chrono::NaiveDateTime> {
     match chrono::NaiveDateTime::parse_from_str(t, "%Y-%m-%dT%H:%M:%SZ") {
         // Prior to KDBX4 file format, timestamps were stored as ISO 8601 strings
@@ -42,293 +34,1238 @@ fn parse_xml_timestamp(t: &str) -> Result<chrono::NaiveDateTime> {
     }
 }
 
-pub(crate) fn parse_xml_block(xml: &[u8],
-                              inner_cipher: &mut dyn Cipher) -> Result<(Option<Metadata>, Group)> {
-    let parser = EventReader::new(xml);
-
-    // Stack of parsed Node objects not yet associated with their parent
-    let mut parsed_stack: Vec<Node> = vec![];
-
-    // Stack of XML element names
-    let mut xml_stack: Vec<String> = vec![];
-
-    let mut root_group: Group = Default::default();
-    let mut metadata: Option<Metadata> = None;
-
-    for e in parser {
-        match e.unwrap() {
-            XmlEvent::StartElement {
-                name: OwnedName { ref local_name, .. },
-                ref attributes,
-                ..
-            } => {
-                xml_stack.push(local_name.clone());
-
-                match &local_name[..] {
-                    "Meta" => parsed_stack.push(Node::Metadata(Default::default())),
-                    "Group" => parsed_stack.push(Node::Group(Default::default())),
-                    "Entry" => parsed_stack.push(Node::Entry(Default::default())),
-                    "String" => parsed_stack.push(Node::KeyValue(
-                        String::new(),
-                        Value::Unprotected(String::new()),
-                    )),
-                    "Value" => {
-                        // Are we encountering a protected value?
-                        if attributes
-                            .iter()
-                            .find(|oa| oa.name.local_name == "Protected")
-                            .map(|oa| &oa.value)
-                            .map_or(false, |v| v.to_lowercase().parse::<bool>().unwrap_or(false))
-                        {
-                            // Transform value to a Value::Protected
-                            if let Some(&mut Node::KeyValue(_, ref mut ev)) =
-                                parsed_stack.last_mut()
-                            {
-                                *ev = Value::Protected(SecStr::new(vec![]));
-                            }
-                        }
-                    }
-                    "AutoType" => parsed_stack.push(Node::AutoType(Default::default())),
-                    "Association" => {
-                        parsed_stack.push(Node::AutoTypeAssociation(Default::default()))
-                    }
-                    "ExpiryTime" => parsed_stack.push(Node::ExpiryTime(String::new())),
-                    "Expires" => parsed_stack.push(Node::Expires(bool::default())),
-                    "IconID" => parsed_stack.push(Node::Icon(Icon::IconID(u8::default()))),
-                    "CustomIconUUID" => parsed_stack.push(Node::Icon(Icon::CustomIcon(String::new()))),
-
-                    // Meta
-                    "Icon" => parsed_stack.push(Node::CustomIcon(String::new(), String::new())),
-                    _ => {}
+fn xml_err(e: quick_xml::Error) -> Error {
+    Error::from(DatabaseIntegrityError::from(e))
+}
+
+fn write_xml_timestamp(t: &chrono::NaiveDateTime, kdbx4: bool) -> String {
+    if kdbx4 {
+        // KDBX4 stores timestamps as the Base64 encoding of the little-endian
+        // i64 number of seconds elapsed since 0001-01-01 00:00:00.
+        let epoch =
+            chrono::NaiveDateTime::parse_from_str("0001-01-01T00:00:00", "%Y-%m-%dT%H:%M:%S")
+                .unwrap();
+        let secs = (*t - epoch).num_seconds();
+        base64::encode(secs.to_le_bytes())
+    } else {
+        // Prior to KDBX4, timestamps were stored as ISO 8601 strings.
+        t.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+    }
+}
+
+/// Serializes a `Group` tree and its `Metadata` back into the inner KDBX XML
+/// payload, mirroring the protected-value handling of `parse_xml_block`:
+/// `Value::Protected` fields are encrypted through the inner cipher's
+/// keystream and Base64-encoded, while `Value::Unprotected` fields are
+/// written out as plain text.
+pub(crate) fn write_xml_block(
+    root: &Group,
+    meta: &Metadata,
+    kdbx4: bool,
+    inner_cipher: &mut dyn Cipher,
+) -> Result<Vec<u8>> {
+    let pool = collect_binary_pool(meta, root);
+
+    let mut out: Vec<u8> = Vec::new();
+    let mut writer = Writer::new_with_indent(&mut out, b' ', 2);
+
+    writer
+        .write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))
+        .map_err(xml_err)?;
+
+    writer
+        .write_event(Event::Start(BytesStart::new("KeePassFile")))
+        .map_err(xml_err)?;
+
+    write_meta(&mut writer, meta, &pool, kdbx4)?;
+
+    writer
+        .write_event(Event::Start(BytesStart::new("Root")))
+        .map_err(xml_err)?;
+    write_group(&mut writer, root, &pool, kdbx4, inner_cipher)?;
+    writer
+        .write_event(Event::End(BytesEnd::new("Root")))
+        .map_err(xml_err)?;
+
+    writer
+        .write_event(Event::End(BytesEnd::new("KeePassFile")))
+        .map_err(xml_err)?;
+
+    Ok(out)
+}
+
+/// Assigns each distinct binary attachment reachable from `meta` or `root`
+/// (including entry history) a stable pool ID, reusing the ID already on
+/// file in `meta.binaries` when the content matches so unchanged attachments
+/// keep their `Ref` across a save. Every `<Binary>` element written by
+/// `write_binary_field` looks its data up here instead of inlining it, so an
+/// attachment shared across entries (or repeated in history) is only ever
+/// written to the pool once.
+fn collect_binary_pool(meta: &Metadata, root: &Group) -> HashMap<Vec<u8>, usize> {
+    let mut pool: HashMap<Vec<u8>, usize> = HashMap::new();
+    let mut next_id = 0;
+    for (id, data) in &meta.binaries {
+        pool.entry(data.clone()).or_insert(*id);
+        next_id = next_id.max(id + 1);
+    }
+
+    fn walk_group(pool: &mut HashMap<Vec<u8>, usize>, next_id: &mut usize, group: &Group) {
+        for node in &group.children {
+            match node {
+                crate::Node::Group(g) => walk_group(pool, next_id, g),
+                crate::Node::Entry(e) => walk_entry(pool, next_id, e),
+            }
+        }
+    }
+
+    fn walk_entry(pool: &mut HashMap<Vec<u8>, usize>, next_id: &mut usize, entry: &Entry) {
+        for value in entry.fields.values() {
+            if let Value::Bytes(data) = value {
+                if !pool.contains_key(data) {
+                    pool.insert(data.clone(), *next_id);
+                    *next_id += 1;
                 }
             }
+        }
+        for previous in &entry.history {
+            walk_entry(pool, next_id, previous);
+        }
+    }
 
-            XmlEvent::EndElement {
-                name: OwnedName { ref local_name, .. },
-            } => {
-                xml_stack.pop();
-
-                if [
-                    "Meta",
-                    "Group",
-                    "Entry",
-                    "String",
-                    "AutoType",
-                    "Association",
-                    "ExpiryTime",
-                    "Expires",
-                    "IconID",
-                    "CustomIconUUID",
-                    "Icon",
-                ]
-                .contains(&&local_name[..])
-                {
-                    let finished_node = parsed_stack.pop().unwrap();
-                    let parsed_stack_head = parsed_stack.last_mut();
-
-                    match finished_node {
-                        Node::KeyValue(k, v) => {
-                            if let Some(&mut Node::Entry(Entry { ref mut fields, .. })) =
-                                parsed_stack_head
-                            {
-                                // A KeyValue was finished inside of an Entry -> add a field
-                                fields.insert(k, v);
-                            }
-                        }
+    walk_group(&mut pool, &mut next_id, root);
+    pool
+}
 
-                        Node::Metadata(m) => {
-                            metadata = Some(m)
-                        }
+fn write_meta(
+    writer: &mut Writer<&mut Vec<u8>>,
+    meta: &Metadata,
+    pool: &HashMap<Vec<u8>, usize>,
+    kdbx4: bool,
+) -> Result<()> {
+    writer
+        .write_event(Event::Start(BytesStart::new("Meta")))
+        .map_err(xml_err)?;
 
-                        Node::Group(finished_group) => {
-                            match parsed_stack_head {
-                                Some(&mut Node::Group(Group {
-                                    ref mut children, ..
-                                })) => {
-                                    // A Group was finished - add Group to children
-                                    children.push(crate::Node::Group(finished_group));
-                                }
-                                None => {
-                                    // There is no more parent nodes left -> we are at the root
-                                    root_group = finished_group;
-                                }
-                                _ => {}
-                            }
-                        }
+    write_text_element(writer, "Generator", &meta.generator)?;
+    write_text_element(writer, "DatabaseName", &meta.name)?;
+    write_text_element(writer, "DatabaseDescription", &meta.description)?;
 
-                        Node::Entry(finished_entry) => {
-                            if let Some(&mut Node::Group(Group {
-                                ref mut children, ..
-                            })) = parsed_stack_head
-                            {
-                                // A Entry was finished - add Node to parent Group's children
-                                children.push(crate::Node::Entry(finished_entry))
-                            }
-                        }
+    writer
+        .write_event(Event::Start(BytesStart::new("CustomIcons")))
+        .map_err(xml_err)?;
+    for (uuid, data) in &meta.custom_icons {
+        writer
+            .write_event(Event::Start(BytesStart::new("Icon")))
+            .map_err(xml_err)?;
+        write_text_element(writer, "UUID", uuid)?;
+        write_text_element(writer, "Data", data)?;
+        writer
+            .write_event(Event::End(BytesEnd::new("Icon")))
+            .map_err(xml_err)?;
+    }
+    writer
+        .write_event(Event::End(BytesEnd::new("CustomIcons")))
+        .map_err(xml_err)?;
 
-                        Node::AutoType(at) => {
-                            if let Some(&mut Node::Entry(Entry {
-                                ref mut autotype, ..
-                            })) = parsed_stack_head
-                            {
-                                autotype.replace(at);
-                            }
-                        }
+    // KDBX4 carries the binary pool in the inner header rather than inline
+    // here; writing it into <Meta><Binaries> as well would be non-conformant
+    // XML and, on re-read, would clobber header-seeded binaries that share
+    // the same ID in Reader's single binaries index.
+    if !kdbx4 {
+        writer
+            .write_event(Event::Start(BytesStart::new("Binaries")))
+            .map_err(xml_err)?;
+        for (data, id) in pool {
+            let id = id.to_string();
+            let mut binary = BytesStart::new("Binary");
+            binary.push_attribute(("ID", id.as_str()));
+            writer.write_event(Event::Start(binary)).map_err(xml_err)?;
+            writer
+                .write_event(Event::Text(BytesText::new(&base64::encode(data))))
+                .map_err(xml_err)?;
+            writer
+                .write_event(Event::End(BytesEnd::new("Binary")))
+                .map_err(xml_err)?;
+        }
+        writer
+            .write_event(Event::End(BytesEnd::new("Binaries")))
+            .map_err(xml_err)?;
+    }
 
-                        Node::AutoTypeAssociation(ata) => {
-                            if let Some(&mut Node::AutoType(AutoType {
-                                ref mut associations,
-                                ..
-                            })) = parsed_stack_head
-                            {
-                                associations.push(ata);
-                            }
-                        }
+    writer
+        .write_event(Event::End(BytesEnd::new("Meta")))
+        .map_err(xml_err)?;
 
-                        Node::ExpiryTime(et) => {
-                            // Currently ingoring any Err() from parse_xml_timestamp()
-                            // Ignoring Err() to avoid possible regressions for existing users
-                            if let Some(&mut Node::Entry(Entry { ref mut times, .. })) =
-                                parsed_stack_head
-                            {
-                                match parse_xml_timestamp(&et) {
-                                    Ok(t) => times.insert("ExpiryTime".to_owned(), t),
-                                    _ => None,
-                                };
-                            } else if let Some(&mut Node::Group(Group { ref mut times, .. })) =
-                                parsed_stack_head
-                            {
-                                match parse_xml_timestamp(&et) {
-                                    Ok(t) => times.insert("ExpiryTime".to_owned(), t),
-                                    _ => None,
-                                };
-                            }
-                        }
+    Ok(())
+}
 
-                        Node::Expires(es) => {
-                            if let Some(&mut Node::Entry(Entry {
-                                ref mut expires, ..
-                            })) = parsed_stack_head
-                            {
-                                *expires = es;
-                            } else if let Some(&mut Node::Group(Group {
-                                ref mut expires, ..
-                            })) = parsed_stack_head
-                            {
-                                *expires = es;
-                            }
-                        }
+fn write_group(
+    writer: &mut Writer<&mut Vec<u8>>,
+    group: &Group,
+    pool: &HashMap<Vec<u8>, usize>,
+    kdbx4: bool,
+    inner_cipher: &mut dyn Cipher,
+) -> Result<()> {
+    writer
+        .write_event(Event::Start(BytesStart::new("Group")))
+        .map_err(xml_err)?;
 
-                        Node::Icon(ic) => {
-                            if let Some(&mut Node::Entry(Entry {
-                                ref mut icon, ..
-                            })) = parsed_stack_head
-                            {
-                                *icon = ic;
-                            } else if let Some(&mut Node::Group(Group {
-                                ref mut icon, ..
-                            })) = parsed_stack_head
-                            {
-                                *icon = ic;
-                            }
-                        }
+    write_text_element(writer, "Name", &group.name)?;
+    write_icon(writer, &group.icon)?;
+    write_times(writer, &group.times, group.expires, None, kdbx4)?;
 
-                        Node::CustomIcon(uuid, data) => {
-                            if let Some(&mut Node::Metadata(Metadata{
-                                ref mut custom_icons, ..
-                            })) = parsed_stack_head {
-                                custom_icons.insert(uuid, data);
-                            }
-                        }
-                    }
+    for node in &group.children {
+        match node {
+            crate::Node::Group(g) => write_group(writer, g, pool, kdbx4, inner_cipher)?,
+            crate::Node::Entry(e) => write_entry(writer, e, pool, kdbx4, inner_cipher)?,
+        }
+    }
+
+    writer
+        .write_event(Event::End(BytesEnd::new("Group")))
+        .map_err(xml_err)?;
+
+    Ok(())
+}
+
+fn write_entry(
+    writer: &mut Writer<&mut Vec<u8>>,
+    entry: &Entry,
+    pool: &HashMap<Vec<u8>, usize>,
+    kdbx4: bool,
+    inner_cipher: &mut dyn Cipher,
+) -> Result<()> {
+    writer
+        .write_event(Event::Start(BytesStart::new("Entry")))
+        .map_err(xml_err)?;
+
+    write_icon(writer, &entry.icon)?;
+    write_times(writer, &entry.times, entry.expires, entry.usage_count, kdbx4)?;
+
+    for (key, value) in &entry.fields {
+        match value {
+            Value::Bytes(data) => write_binary_field(writer, key, data, pool)?,
+            _ => write_string_field(writer, key, value, inner_cipher, kdbx4)?,
+        }
+    }
+
+    if let Some(autotype) = &entry.autotype {
+        write_autotype(writer, autotype)?;
+    }
+
+    if !entry.history.is_empty() {
+        writer
+            .write_event(Event::Start(BytesStart::new("History")))
+            .map_err(xml_err)?;
+        for previous in &entry.history {
+            write_entry(writer, previous, pool, kdbx4, inner_cipher)?;
+        }
+        writer
+            .write_event(Event::End(BytesEnd::new("History")))
+            .map_err(xml_err)?;
+    }
+
+    writer
+        .write_event(Event::End(BytesEnd::new("Entry")))
+        .map_err(xml_err)?;
+
+    Ok(())
+}
+
+fn write_string_field(
+    writer: &mut Writer<&mut Vec<u8>>,
+    key: &str,
+    value: &Value,
+    inner_cipher: &mut dyn Cipher,
+    kdbx4: bool,
+) -> Result<()> {
+    writer
+        .write_event(Event::Start(BytesStart::new("String")))
+        .map_err(xml_err)?;
+    write_text_element(writer, "Key", key)?;
+
+    match value {
+        Value::Unprotected(v) => {
+            write_text_element(writer, "Value", v)?;
+        }
+        Value::Protected(v) => {
+            // The inner cipher is a stream cipher: re-applying its keystream
+            // to the plaintext produces the ciphertext, same as decryption.
+            let encrypted = inner_cipher.decrypt(v.unsecure())?;
+            let mut value_tag = BytesStart::new("Value");
+            value_tag.push_attribute(("Protected", "True"));
+            writer.write_event(Event::Start(value_tag)).map_err(xml_err)?;
+            writer
+                .write_event(Event::Text(BytesText::new(&base64::encode(encrypted))))
+                .map_err(xml_err)?;
+            writer
+                .write_event(Event::End(BytesEnd::new("Value")))
+                .map_err(xml_err)?;
+        }
+        // A Typed value only ever comes from a FieldConversion applied to an
+        // originally-unprotected string, so it goes back out the same way.
+        Value::Typed(t) => {
+            write_text_element(writer, "Value", &format_typed_value(t, kdbx4))?;
+        }
+        Value::Bytes(_) => {} // binary attachments are written via write_binary_field, not here
+    }
+
+    writer
+        .write_event(Event::End(BytesEnd::new("String")))
+        .map_err(xml_err)?;
+
+    Ok(())
+}
+
+fn write_binary_field(
+    writer: &mut Writer<&mut Vec<u8>>,
+    key: &str,
+    data: &[u8],
+    pool: &HashMap<Vec<u8>, usize>,
+) -> Result<()> {
+    // `pool` was built from this same `Group`/`Metadata` tree by
+    // `collect_binary_pool`, so every attachment reachable here has an ID.
+    let id = pool[data].to_string();
+
+    writer
+        .write_event(Event::Start(BytesStart::new("Binary")))
+        .map_err(xml_err)?;
+    write_text_element(writer, "Key", key)?;
+    let mut value_tag = BytesStart::new("Value");
+    value_tag.push_attribute(("Ref", id.as_str()));
+    writer.write_event(Event::Empty(value_tag)).map_err(xml_err)?;
+    writer
+        .write_event(Event::End(BytesEnd::new("Binary")))
+        .map_err(xml_err)?;
+
+    Ok(())
+}
+
+fn write_autotype(writer: &mut Writer<&mut Vec<u8>>, autotype: &AutoType) -> Result<()> {
+    writer
+        .write_event(Event::Start(BytesStart::new("AutoType")))
+        .map_err(xml_err)?;
+
+    write_text_element(writer, "Enabled", &autotype.enabled.to_string())?;
+    if let Some(sequence) = &autotype.sequence {
+        write_text_element(writer, "DefaultSequence", sequence)?;
+    }
+
+    for association in &autotype.associations {
+        writer
+            .write_event(Event::Start(BytesStart::new("Association")))
+            .map_err(xml_err)?;
+        if let Some(window) = &association.window {
+            write_text_element(writer, "Window", window)?;
+        }
+        if let Some(sequence) = &association.sequence {
+            write_text_element(writer, "KeystrokeSequence", sequence)?;
+        }
+        writer
+            .write_event(Event::End(BytesEnd::new("Association")))
+            .map_err(xml_err)?;
+    }
+
+    writer
+        .write_event(Event::End(BytesEnd::new("AutoType")))
+        .map_err(xml_err)?;
+
+    Ok(())
+}
+
+fn write_times(
+    writer: &mut Writer<&mut Vec<u8>>,
+    times: &std::collections::HashMap<String, chrono::NaiveDateTime>,
+    expires: bool,
+    usage_count: Option<usize>,
+    kdbx4: bool,
+) -> Result<()> {
+    writer
+        .write_event(Event::Start(BytesStart::new("Times")))
+        .map_err(xml_err)?;
+
+    for tag in TIMESTAMP_TAGS {
+        if let Some(t) = times.get(*tag) {
+            write_text_element(writer, tag, &write_xml_timestamp(t, kdbx4))?;
+        }
+    }
+    write_text_element(writer, "Expires", if expires { "True" } else { "False" })?;
+    if let Some(usage_count) = usage_count {
+        write_text_element(writer, "UsageCount", &usage_count.to_string())?;
+    }
+
+    writer
+        .write_event(Event::End(BytesEnd::new("Times")))
+        .map_err(xml_err)?;
+
+    Ok(())
+}
+
+fn format_typed_value(value: &TypedValue, kdbx4: bool) -> String {
+    match value {
+        TypedValue::Integer(i) => i.to_string(),
+        TypedValue::Float(f) => f.to_string(),
+        TypedValue::Boolean(b) => b.to_string(),
+        // A Standard timestamp is written in the KDBX-version default format,
+        // whichever of ISO-8601 or base64 it was parsed from; a custom
+        // TimestampFmt field is re-serialized with its own format string, so
+        // it round-trips unchanged.
+        TypedValue::Timestamp(t, TimestampFormat::Standard) => write_xml_timestamp(t, kdbx4),
+        TypedValue::Timestamp(t, TimestampFormat::Custom(fmt)) => t.format(fmt).to_string(),
+    }
+}
+
+fn write_icon(writer: &mut Writer<&mut Vec<u8>>, icon: &Icon) -> Result<()> {
+    match icon {
+        Icon::IconID(id) => write_text_element(writer, "IconID", &id.to_string()),
+        Icon::CustomIcon(uuid) => write_text_element(writer, "CustomIconUUID", uuid),
+    }
+}
+
+fn write_text_element(writer: &mut Writer<&mut Vec<u8>>, name: &str, text: &str) -> Result<()> {
+    writer
+        .write_event(Event::Start(BytesStart::new(name)))
+        .map_err(xml_err)?;
+    writer
+        .write_event(Event::Text(BytesText::new(text)))
+        .map_err(xml_err)?;
+    writer
+        .write_event(Event::End(BytesEnd::new(name)))
+        .map_err(xml_err)?;
+    Ok(())
+}
+
+fn local_name_str(name: QName) -> String {
+    String::from_utf8_lossy(name.local_name().as_ref()).into_owned()
+}
+
+fn event_local_name(e: &Event) -> Option<String> {
+    match e {
+        Event::Start(b) | Event::Empty(b) => Some(local_name_str(b.name())),
+        Event::End(b) => Some(local_name_str(b.name())),
+        _ => None,
+    }
+}
+
+/// Builds the `Err` returned when a call site expected a specific element
+/// and got something else, instead of panicking on malformed input.
+fn unexpected_event(expected: &str, found: &Event) -> Error {
+    Error::from(DatabaseIntegrityError::InvalidXMLEvent {
+        expected: expected.to_string(),
+        found: format!("{:?}", found),
+    })
+}
+
+/// A pull-reader over the inner KDBX XML payload, wrapping a
+/// `quick_xml::NsReader` together with the inner cipher needed to decrypt
+/// `Protected` values, and the pool of binary attachments that `<Binary Ref>`
+/// elements resolve against. Namespaces are resolved but not otherwise
+/// checked, and every returned event is cloned to `'static` rather than
+/// borrowing from the internal read buffer.
+struct Reader<'x, 'c, 'f> {
+    reader: NsReader<&'x [u8]>,
+    buf: Vec<u8>,
+    peeked: Option<Event<'static>>,
+    inner_cipher: &'c mut dyn Cipher,
+    binaries: HashMap<usize, Vec<u8>>,
+    field_conversions: &'f FieldConversions,
+}
+
+impl<'x, 'c, 'f> Reader<'x, 'c, 'f> {
+    fn new(
+        xml: &'x [u8],
+        inner_cipher: &'c mut dyn Cipher,
+        header_binaries: &[Vec<u8>],
+        field_conversions: &'f FieldConversions,
+    ) -> Self {
+        let mut reader = NsReader::from_reader(xml);
+        reader.config_mut().trim_text(true);
+        Reader {
+            reader,
+            buf: Vec::new(),
+            peeked: None,
+            inner_cipher,
+            // KDBX4 carries the binary pool in the inner header rather than
+            // inline in <Meta><Binaries>; seed the pool with it so <Binary
+            // Ref="n"> lookups work regardless of where the pool came from.
+            binaries: header_binaries.iter().cloned().enumerate().collect(),
+            field_conversions,
+        }
+    }
+
+    fn next(&mut self) -> Result<Event<'static>> {
+        if let Some(e) = self.peeked.take() {
+            return Ok(e);
+        }
+        loop {
+            self.buf.clear();
+            let (_ns, event) = self
+                .reader
+                .read_resolved_event_into(&mut self.buf)
+                .map_err(xml_err)?;
+            match event {
+                // The XML declaration, comments and processing instructions
+                // may legally precede (or be interspersed around) the root
+                // element; none of them carry content this reader cares
+                // about, so skip straight past them rather than making every
+                // call site account for them.
+                Event::Decl(_) | Event::Comment(_) | Event::PI(_) => continue,
+                other => return Ok(other.into_owned()),
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Result<&Event<'static>> {
+        if self.peeked.is_none() {
+            let e = self.next()?;
+            self.peeked = Some(e);
+        }
+        Ok(self.peeked.as_ref().unwrap())
+    }
+
+    /// Consumes the next event, asserting it is the opening tag `tag`, and
+    /// returns its attributes.
+    fn open(&mut self, tag: &str) -> Result<HashMap<String, String>> {
+        match self.next()? {
+            Event::Start(e) if local_name_str(e.name()) == tag => Ok(read_attributes(&e)),
+            Event::Empty(e) if local_name_str(e.name()) == tag => Ok(read_attributes(&e)),
+            other => Err(unexpected_event(&format!("<{}>", tag), &other)),
+        }
+    }
+
+    /// Consumes the upcoming element and its whole subtree if it is not one
+    /// this reader knows how to interpret.
+    fn skip_element(&mut self) -> Result<()> {
+        match self.next()? {
+            Event::Start(e) => {
+                let name = e.name().into_owned();
+                self.reader
+                    .read_to_end_into(QName(name.as_ref()), &mut self.buf)
+                    .map_err(xml_err)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The fallback arm of a child-dispatch loop: if the upcoming event is
+    /// the closing tag `tag`, consumes it and returns `true` so the caller
+    /// can break out of its loop; otherwise skips one unrecognized event (and
+    /// its subtree, if it opens one) and returns `false` so the loop can keep
+    /// dispatching.
+    fn maybe_close(&mut self, tag: &str) -> Result<bool> {
+        match self.peek()? {
+            Event::End(e) if local_name_str(e.name()) == tag => {
+                self.next()?;
+                Ok(true)
+            }
+            Event::Start(_) => {
+                self.skip_element()?;
+                Ok(false)
+            }
+            _ => {
+                self.next()?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Skips unknown elements and consumes the closing tag `tag`.
+    fn close(&mut self, tag: &str) -> Result<()> {
+        loop {
+            match self.next()? {
+                Event::End(e) if local_name_str(e.name()) == tag => return Ok(()),
+                Event::Start(e) => {
+                    let name = e.name().into_owned();
+                    self.reader
+                        .read_to_end_into(QName(name.as_ref()), &mut self.buf)
+                        .map_err(xml_err)?;
                 }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads zero or more consecutive children of type `T`, stopping as soon
+    /// as the upcoming element isn't tagged `tag`.
+    fn collect<T: QRead>(&mut self, tag: &str) -> Result<Vec<T>> {
+        let mut out = Vec::new();
+        while matches!(event_local_name(self.peek()?), Some(ref n) if n == tag) {
+            out.push(T::qread(self)?);
+        }
+        Ok(out)
+    }
+
+    /// Reads the text content of a simple element whose opening tag was
+    /// already consumed, then consumes its matching closing tag. Only a
+    /// `Start` opening tag may precede this: a self-closing `<Tag/>` emits no
+    /// separate `End`, so that case is handled by `open_text` instead.
+    fn read_text(&mut self, tag: &str) -> Result<String> {
+        match self.next()? {
+            Event::Text(t) => {
+                let text = t
+                    .unescape()
+                    .map_err(xml_err)?
+                    .into_owned();
+                self.close(tag)?;
+                Ok(text)
+            }
+            Event::End(e) if local_name_str(e.name()) == tag => Ok(String::new()),
+            other => Err(unexpected_event(&format!("text in <{}>", tag), &other)),
+        }
+    }
+
+    /// Consumes a leaf element `tag` (either `<Tag>text</Tag>` or the
+    /// self-closing `<Tag/>`) and returns its attributes and text content.
+    fn open_text(&mut self, tag: &str) -> Result<(HashMap<String, String>, String)> {
+        match self.next()? {
+            Event::Empty(e) if local_name_str(e.name()) == tag => {
+                Ok((read_attributes(&e), String::new()))
             }
+            Event::Start(e) if local_name_str(e.name()) == tag => {
+                let attrs = read_attributes(&e);
+                let text = self.read_text(tag)?;
+                Ok((attrs, text))
+            }
+            other => Err(unexpected_event(&format!("<{}>", tag), &other)),
+        }
+    }
+
+    /// Shorthand for `open_text` when the attributes aren't needed.
+    fn text(&mut self, tag: &str) -> Result<String> {
+        Ok(self.open_text(tag)?.1)
+    }
+}
+
+fn read_attributes(e: &BytesStart) -> HashMap<String, String> {
+    e.attributes()
+        .filter_map(|a| a.ok())
+        .map(|a| {
+            (
+                String::from_utf8_lossy(a.key.local_name().as_ref()).into_owned(),
+                String::from_utf8_lossy(&a.value).into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// A type that knows how to read its own element (and nothing past its
+/// matching closing tag) off of a `Reader`.
+trait QRead: Sized {
+    fn qread(r: &mut Reader) -> Result<Self>;
+}
+
+/// The target type a custom `<String>` field's value should be converted to,
+/// keyed by field name in a [`FieldConversions`] map.
+#[derive(Debug, Clone)]
+pub enum FieldConversion {
+    Integer,
+    Float,
+    Boolean,
+    Timestamp,
+    /// Like `Timestamp`, but parsed with an explicit chrono format string
+    /// instead of the ISO-8601/KDBX4 formats `parse_xml_timestamp` expects.
+    TimestampFmt(String),
+}
+
+/// An opt-in map of field name to the [`FieldConversion`] that should be
+/// attempted on it, passed alongside the inner cipher when parsing.
+pub type FieldConversions = HashMap<String, FieldConversion>;
+
+/// The result of successfully applying a `FieldConversion` to a custom
+/// field's raw string value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypedValue {
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Timestamp(chrono::NaiveDateTime, TimestampFormat),
+}
+
+/// Which textual representation a `TypedValue::Timestamp` was parsed from,
+/// kept alongside the value so the writer can round-trip it back to the same
+/// representation instead of always re-rendering it in the KDBX-version
+/// default format.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimestampFormat {
+    /// `parse_xml_timestamp`'s ISO-8601/KDBX4 base64 encoding.
+    Standard,
+    /// An explicit chrono format string, from `FieldConversion::TimestampFmt`.
+    Custom(String),
+}
 
-            XmlEvent::Characters(c) => {
-                // Got some character data that need to be matched to a Node on the parsed_stack.
+/// Attempts `conversion` against `text`, returning `None` on any parse
+/// failure so the caller can fall back to the original string value.
+fn convert_field(conversion: &FieldConversion, text: &str) -> Option<TypedValue> {
+    match conversion {
+        FieldConversion::Integer => text.parse().ok().map(TypedValue::Integer),
+        FieldConversion::Float => text.parse().ok().map(TypedValue::Float),
+        FieldConversion::Boolean => text
+            .to_lowercase()
+            .parse()
+            .ok()
+            .map(TypedValue::Boolean),
+        FieldConversion::Timestamp => parse_xml_timestamp(text)
+            .ok()
+            .map(|t| TypedValue::Timestamp(t, TimestampFormat::Standard)),
+        FieldConversion::TimestampFmt(fmt) => chrono::NaiveDateTime::parse_from_str(text, fmt)
+            .ok()
+            .map(|t| TypedValue::Timestamp(t, TimestampFormat::Custom(fmt.clone()))),
+    }
+}
+
+impl QRead for Value {
+    fn qread(r: &mut Reader) -> Result<Self> {
+        let (attrs, text) = r.open_text("Value")?;
+        let protected = attrs
+            .get("Protected")
+            .map_or(false, |v| v.to_lowercase().parse::<bool>().unwrap_or(false));
+
+        if protected {
+            let buf = base64::decode(&text)
+                .map_err(|e| Error::from(DatabaseIntegrityError::from(e)))?;
+            let buf_decode = r.inner_cipher.decrypt(&buf)?;
+            let c_decode = std::str::from_utf8(&buf_decode)
+                .map_err(|e| Error::from(DatabaseIntegrityError::from(e)))?;
+            Ok(Value::Protected(SecStr::from(c_decode)))
+        } else {
+            Ok(Value::Unprotected(text))
+        }
+    }
+}
 
-                match (xml_stack.last().map(|s| &s[..]), parsed_stack.last_mut()) {
-                    (Some("Name"), Some(&mut Node::Group(Group { ref mut name, .. }))) => {
-                        // Got a "Name" element with a Node::Group on the parsed_stack
-                        // Update the Group's name
-                        *name = c;
+impl QRead for AutoTypeAssociation {
+    fn qread(r: &mut Reader) -> Result<Self> {
+        r.open("Association")?;
+        let mut association = AutoTypeAssociation::default();
+        loop {
+            match event_local_name(r.peek()?).as_deref() {
+                Some("Window") => {
+                    association.window = Some(r.text("Window")?);
+                }
+                Some("KeystrokeSequence") => {
+                    association.sequence = Some(r.text("KeystrokeSequence")?);
+                }
+                _ => {
+                    if r.maybe_close("Association")? {
+                        break;
                     }
-                    (Some("ExpiryTime"), Some(&mut Node::ExpiryTime(ref mut et))) => {
-                        *et = c;
+                }
+            }
+        }
+        Ok(association)
+    }
+}
+
+impl QRead for AutoType {
+    fn qread(r: &mut Reader) -> Result<Self> {
+        r.open("AutoType")?;
+        let mut autotype = AutoType::default();
+        loop {
+            match event_local_name(r.peek()?).as_deref() {
+                Some("Enabled") => {
+                    autotype.enabled = r.text("Enabled")?.parse().unwrap_or(false);
+                }
+                Some("DefaultSequence") => {
+                    autotype.sequence = Some(r.text("DefaultSequence")?);
+                }
+                Some("Association") => {
+                    autotype.associations = r.collect("Association")?;
+                }
+                _ => {
+                    if r.maybe_close("AutoType")? {
+                        break;
                     }
-                    (Some("Expires"), Some(&mut Node::Expires(ref mut es))) => {
-                        *es = c == "True";
+                }
+            }
+        }
+        Ok(autotype)
+    }
+}
+
+// Every timestamp element directly under <Times>, keyed by its own tag name
+// when stored into `Entry`/`Group`'s `times` map.
+const TIMESTAMP_TAGS: &[&str] = &[
+    "ExpiryTime",
+    "CreationTime",
+    "LastModificationTime",
+    "LastAccessTime",
+    "LocationChanged",
+];
+
+/// Holds the parsed contents of a `<Times>` block for merging into whichever
+/// `Entry`/`Group` it belongs to.
+#[derive(Default)]
+struct Times {
+    values: HashMap<String, chrono::NaiveDateTime>,
+    expires: bool,
+    usage_count: Option<usize>,
+}
+
+impl QRead for Times {
+    fn qread(r: &mut Reader) -> Result<Self> {
+        r.open("Times")?;
+        let mut times = Times::default();
+        loop {
+            let tag = event_local_name(r.peek()?);
+            match tag.as_deref() {
+                Some(t) if TIMESTAMP_TAGS.contains(&t) => {
+                    let t = t.to_owned();
+                    let text = r.text(&t)?;
+                    if let Ok(ndt) = parse_xml_timestamp(&text) {
+                        times.values.insert(t, ndt);
                     }
-                    (Some("Key"), Some(&mut Node::KeyValue(ref mut k, _))) => {
-                        // Got a "Key" element with a Node::KeyValue on the parsed_stack
-                        // Update the KeyValue's key
-                        *k = c;
+                }
+                Some("Expires") => {
+                    times.expires = r.text("Expires")? == "True";
+                }
+                Some("UsageCount") => {
+                    times.usage_count = r.text("UsageCount")?.parse().ok();
+                }
+                _ => {
+                    if r.maybe_close("Times")? {
+                        break;
                     }
-                    (Some("Value"), Some(&mut Node::KeyValue(_, ref mut ev))) => {
-                        // Got a "Value" element with a Node::KeyValue on the parsed_stack
-                        // Update the KeyValue's value
-
-                        match *ev {
-                            Value::Bytes(_) => {} // not possible
-                            Value::Unprotected(ref mut v) => {
-                                *v = c;
-                            }
-                            Value::Protected(ref mut v) => {
-                                // Use the decryptor to decrypt the protected
-                                // and base64-encoded value
-                                //
-                                let buf = base64::decode(&c)
-                                    .map_err(|e| Error::from(DatabaseIntegrityError::from(e)))?;
-
-                                let buf_decode = inner_cipher.decrypt(&buf)?;
+                }
+            }
+        }
+        Ok(times)
+    }
+}
 
-                                let c_decode = std::str::from_utf8(&buf_decode)
-                                    .map_err(|e| Error::from(DatabaseIntegrityError::from(e)))?;
+impl QRead for Icon {
+    fn qread(r: &mut Reader) -> Result<Self> {
+        match event_local_name(r.peek()?).as_deref() {
+            Some("IconID") => {
+                Ok(Icon::IconID(r.text("IconID")?.parse().unwrap_or(0)))
+            }
+            Some("CustomIconUUID") => {
+                Ok(Icon::CustomIcon(r.text("CustomIconUUID")?))
+            }
+            _ => Err(unexpected_event("an icon element", r.peek()?)),
+        }
+    }
+}
 
-                                *v = SecStr::from(c_decode);
+impl QRead for Entry {
+    fn qread(r: &mut Reader) -> Result<Self> {
+        r.open("Entry")?;
+        let mut entry = Entry::default();
+        loop {
+            match event_local_name(r.peek()?).as_deref() {
+                Some("String") => {
+                    r.open("String")?;
+                    let mut key = String::new();
+                    let mut value = Value::Unprotected(String::new());
+                    loop {
+                        match event_local_name(r.peek()?).as_deref() {
+                            Some("Key") => {
+                                key = r.text("Key")?;
+                            }
+                            Some("Value") => {
+                                value = Value::qread(r)?;
+                            }
+                            _ => {
+                                if r.maybe_close("String")? {
+                                    break;
+                                }
                             }
                         }
                     }
-                    (Some("Enabled"), Some(&mut Node::AutoType(ref mut at))) => {
-                        at.enabled = c.parse().unwrap_or(false);
-                    }
-                    (Some("DefaultSequence"), Some(&mut Node::AutoType(ref mut at))) => {
-                        at.sequence = Some(c.to_owned());
-                    }
-                    (Some("Window"), Some(&mut Node::AutoTypeAssociation(ref mut ata))) => {
-                        ata.window = Some(c.to_owned());
-                    }
-                    (Some("KeystrokeSequence"), Some(&mut Node::AutoTypeAssociation(ref mut ata))) => {
-                        ata.sequence = Some(c.to_owned());
+                    if let Value::Unprotected(text) = &value {
+                        if let Some(conversion) = r.field_conversions.get(&key) {
+                            if let Some(typed) = convert_field(conversion, text) {
+                                value = Value::Typed(typed);
+                            }
+                        }
                     }
-                    (Some("IconID"), Some(&mut Node::Icon(Icon::IconID(ref mut icon)))) => {
-                        *icon = c.parse().unwrap_or(0);
+                    entry.fields.insert(key, value);
+                }
+                Some("AutoType") => entry.autotype = Some(AutoType::qread(r)?),
+                Some("Times") => {
+                    let times = Times::qread(r)?;
+                    entry.times = times.values;
+                    entry.expires = times.expires;
+                    entry.usage_count = times.usage_count;
+                }
+                Some("IconID") | Some("CustomIconUUID") => entry.icon = Icon::qread(r)?,
+                Some("History") => {
+                    r.open("History")?;
+                    entry.history = r.collect("Entry")?;
+                    r.close("History")?;
+                }
+                Some("Binary") => {
+                    r.open("Binary")?;
+                    let mut key = String::new();
+                    let mut bytes = None;
+                    loop {
+                        match event_local_name(r.peek()?).as_deref() {
+                            Some("Key") => {
+                                key = r.text("Key")?;
+                            }
+                            Some("Value") => {
+                                let (attrs, text) = r.open_text("Value")?;
+                                let protected = attrs.get("Protected").map_or(false, |v| {
+                                    v.to_lowercase().parse::<bool>().unwrap_or(false)
+                                });
+                                if let Some(raw) = attrs
+                                    .get("Ref")
+                                    .and_then(|v| v.parse::<usize>().ok())
+                                    .and_then(|id| r.binaries.get(&id).cloned())
+                                {
+                                    bytes = Some(if protected {
+                                        r.inner_cipher.decrypt(&raw)?
+                                    } else {
+                                        raw
+                                    });
+                                } else if !text.is_empty() {
+                                    // Some writers inline the binary instead of referencing the pool.
+                                    if let Ok(decoded) = base64::decode(&text) {
+                                        bytes = Some(if protected {
+                                            r.inner_cipher.decrypt(&decoded)?
+                                        } else {
+                                            decoded
+                                        });
+                                    }
+                                }
+                            }
+                            _ => {
+                                if r.maybe_close("Binary")? {
+                                    break;
+                                }
+                            }
+                        }
                     }
-                    (Some("CustomIconUUID"), Some(&mut Node::Icon(Icon::CustomIcon(ref mut icon)))) => {
-                        *icon = c;
+                    if let Some(data) = bytes {
+                        entry.fields.insert(key, Value::Bytes(data));
                     }
-                    (Some("Generator"), Some(&mut Node::Metadata(ref mut mdt))) => {
-                        mdt.generator = c;
+                }
+                _ => {
+                    if r.maybe_close("Entry")? {
+                        break;
                     }
-                    (Some("DatabaseName"), Some(&mut Node::Metadata(ref mut mdt))) => {
-                        mdt.name = c;
+                }
+            }
+        }
+        Ok(entry)
+    }
+}
+
+impl QRead for Group {
+    fn qread(r: &mut Reader) -> Result<Self> {
+        r.open("Group")?;
+        let mut group = Group::default();
+        loop {
+            match event_local_name(r.peek()?).as_deref() {
+                Some("Name") => {
+                    group.name = r.text("Name")?;
+                }
+                Some("Times") => {
+                    let times = Times::qread(r)?;
+                    group.times = times.values;
+                    group.expires = times.expires;
+                }
+                Some("IconID") | Some("CustomIconUUID") => group.icon = Icon::qread(r)?,
+                // Subgroups and entries can be interleaved in document order
+                // (e.g. a group reordered or added to after an existing
+                // entry), so each is pushed to `children` as it's seen rather
+                // than collecting all of one tag before the other.
+                Some("Group") => {
+                    let child = Group::qread(r)?;
+                    group.children.push(crate::Node::Group(child));
+                }
+                Some("Entry") => {
+                    let child = Entry::qread(r)?;
+                    group.children.push(crate::Node::Entry(child));
+                }
+                _ => {
+                    if r.maybe_close("Group")? {
+                        break;
                     }
-                    (Some("DatabaseDescription"), Some(&mut Node::Metadata(ref mut mdt))) => {
-                        mdt.description = c;
+                }
+            }
+        }
+        Ok(group)
+    }
+}
+
+impl QRead for Metadata {
+    fn qread(r: &mut Reader) -> Result<Self> {
+        r.open("Meta")?;
+        let mut meta = Metadata::default();
+        loop {
+            match event_local_name(r.peek()?).as_deref() {
+                Some("Generator") => {
+                    meta.generator = r.text("Generator")?;
+                }
+                Some("DatabaseName") => {
+                    meta.name = r.text("DatabaseName")?;
+                }
+                Some("DatabaseDescription") => {
+                    meta.description = r.text("DatabaseDescription")?;
+                }
+                Some("CustomIcons") => {
+                    r.open("CustomIcons")?;
+                    loop {
+                        match event_local_name(r.peek()?).as_deref() {
+                            Some("Icon") => {
+                                r.open("Icon")?;
+                                let mut uuid = String::new();
+                                let mut data = String::new();
+                                loop {
+                                    match event_local_name(r.peek()?).as_deref() {
+                                        Some("UUID") => {
+                                            uuid = r.text("UUID")?;
+                                        }
+                                        Some("Data") => {
+                                            data = r.text("Data")?;
+                                        }
+                                        _ => {
+                                            if r.maybe_close("Icon")? {
+                                                break;
+                                            }
+                                        }
+                                    }
+                                }
+                                meta.custom_icons.insert(uuid, data);
+                            }
+                            _ => {
+                                if r.maybe_close("CustomIcons")? {
+                                    break;
+                                }
+                            }
+                        }
                     }
-                    (Some("UUID"), Some(&mut Node::CustomIcon(ref mut uuid, _))) => {
-                        *uuid = c;
+                }
+                Some("Binaries") => {
+                    r.open("Binaries")?;
+                    loop {
+                        match event_local_name(r.peek()?).as_deref() {
+                            Some("Binary") => {
+                                let (attrs, text) = r.open_text("Binary")?;
+                                let id = attrs.get("ID").and_then(|v| v.parse::<usize>().ok());
+                                let compressed = attrs
+                                    .get("Compressed")
+                                    .map_or(false, |v| {
+                                        v.to_lowercase().parse::<bool>().unwrap_or(false)
+                                    });
+                                if let Some(id) = id {
+                                    let raw = base64::decode(&text)
+                                        .map_err(|e| Error::from(DatabaseIntegrityError::from(e)))?;
+                                    let data = if compressed {
+                                        let mut decoder = GzDecoder::new(&raw[..]);
+                                        let mut out = Vec::new();
+                                        decoder
+                                            .read_to_end(&mut out)
+                                            .map_err(|e| Error::from(DatabaseIntegrityError::from(e)))?;
+                                        out
+                                    } else {
+                                        raw
+                                    };
+                                    r.binaries.insert(id, data);
+                                }
+                            }
+                            _ => {
+                                if r.maybe_close("Binaries")? {
+                                    break;
+                                }
+                            }
+                        }
                     }
-                    (Some("Data"), Some(&mut Node::CustomIcon(_, ref mut data))) => {
-                        *data = c;
+                }
+                _ => {
+                    if r.maybe_close("Meta")? {
+                        break;
                     }
-                    _ => {}
                 }
             }
+        }
+        meta.binaries = r.binaries.clone();
+        Ok(meta)
+    }
+}
 
-            _ => {}
+pub(crate) fn parse_xml_block(
+    xml: &[u8],
+    inner_cipher: &mut dyn Cipher,
+    header_binaries: &[Vec<u8>],
+    field_conversions: &FieldConversions,
+) -> Result<(Option<Metadata>, Group)> {
+    let mut r = Reader::new(xml, inner_cipher, header_binaries, field_conversions);
+
+    let mut metadata = None;
+    let mut root_group = Group::default();
+
+    r.open("KeePassFile")?;
+    loop {
+        match event_local_name(r.peek()?).as_deref() {
+            Some("Meta") => metadata = Some(Metadata::qread(&mut r)?),
+            Some("Root") => {
+                r.open("Root")?;
+                root_group = Group::qread(&mut r)?;
+                r.close("Root")?;
+            }
+            _ => {
+                if r.maybe_close("KeePassFile")? {
+                    break;
+                }
+            }
         }
     }
 
     Ok((metadata, root_group))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Node;
+
+    /// A trivial XOR stream cipher, good enough to exercise the protected-
+    /// value encrypt/decrypt round-trip in tests without pulling in a real
+    /// KDBX key derivation pipeline. XOR is its own inverse, so `decrypt`
+    /// doubles as the writer's "encrypt".
+    struct TestCipher {
+        keystream: [u8; 4],
+    }
+
+    impl Cipher for TestCipher {
+        fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+            Ok(ciphertext
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ self.keystream[i % self.keystream.len()])
+                .collect())
+        }
+    }
+
+    fn test_cipher() -> TestCipher {
+        TestCipher { keystream: [0x5a, 0x3c, 0x91, 0x10] }
+    }
+
+    fn first_entry(group: &Group) -> &Entry {
+        match &group.children[0] {
+            Node::Entry(e) => e,
+            other => panic!("expected an entry, found {:?}", other),
+        }
+    }
+
+    fn sample_group() -> (Group, Metadata) {
+        let mut entry = Entry::default();
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected("Example".to_string()));
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Protected(SecStr::from("hunter2")),
+        );
+        entry.times.insert(
+            "CreationTime".to_string(),
+            chrono::NaiveDateTime::parse_from_str("2020-01-01T00:00:00Z", "%Y-%m-%dT%H:%M:%SZ")
+                .unwrap(),
+        );
+
+        let mut history_entry = entry.clone();
+        history_entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected("Old Example".to_string()));
+        entry.history = vec![history_entry];
+
+        let mut group = Group::default();
+        group.name = "Root".to_string();
+        group.children.push(Node::Entry(entry));
+
+        (group, Metadata::default())
+    }
+
+    fn round_trip(group: &Group, meta: &Metadata, kdbx4: bool, field_conversions: &FieldConversions) -> Group {
+        let xml = write_xml_block(group, meta, kdbx4, &mut test_cipher()).unwrap();
+        let (_meta, parsed) =
+            parse_xml_block(&xml, &mut test_cipher(), &[], field_conversions).unwrap();
+        parsed
+    }
+
+    #[test]
+    fn round_trips_protected_values_and_history() {
+        let (group, meta) = sample_group();
+        let parsed = round_trip(&group, &meta, false, &FieldConversions::new());
+
+        let entry = first_entry(&parsed);
+        assert_eq!(
+            entry.fields.get("Title"),
+            Some(&Value::Unprotected("Example".to_string()))
+        );
+        match entry.fields.get("Password") {
+            Some(Value::Protected(v)) => assert_eq!(v.unsecure(), b"hunter2"),
+            other => panic!("expected a protected password, found {:?}", other),
+        }
+
+        assert_eq!(entry.history.len(), 1);
+        assert_eq!(
+            entry.history[0].fields.get("Title"),
+            Some(&Value::Unprotected("Old Example".to_string()))
+        );
+    }
+
+    #[test]
+    fn round_trips_timestamps_across_kdbx_versions() {
+        let (group, meta) = sample_group();
+        let expected = first_entry(&group).times.get("CreationTime").cloned();
+
+        for kdbx4 in [false, true] {
+            let parsed = round_trip(&group, &meta, kdbx4, &FieldConversions::new());
+            assert_eq!(
+                first_entry(&parsed).times.get("CreationTime").cloned(),
+                expected,
+                "creation time did not round-trip for kdbx4={}",
+                kdbx4
+            );
+        }
+    }
+
+    #[test]
+    fn round_trips_binary_attachments() {
+        let mut entry = Entry::default();
+        entry.fields.insert(
+            "attachment.txt".to_string(),
+            Value::Bytes(b"file contents".to_vec()),
+        );
+        let mut group = Group::default();
+        group.children.push(Node::Entry(entry));
+        let meta = Metadata::default();
+
+        let parsed = round_trip(&group, &meta, false, &FieldConversions::new());
+
+        assert_eq!(
+            first_entry(&parsed).fields.get("attachment.txt"),
+            Some(&Value::Bytes(b"file contents".to_vec()))
+        );
+    }
+
+    #[test]
+    fn applies_opt_in_field_conversions() {
+        let mut entry = Entry::default();
+        entry
+            .fields
+            .insert("Enabled".to_string(), Value::Unprotected("True".to_string()));
+        let mut group = Group::default();
+        group.children.push(Node::Entry(entry));
+        let meta = Metadata::default();
+
+        let mut conversions = FieldConversions::new();
+        conversions.insert("Enabled".to_string(), FieldConversion::Boolean);
+        let parsed = round_trip(&group, &meta, false, &conversions);
+
+        assert_eq!(
+            first_entry(&parsed).fields.get("Enabled"),
+            Some(&Value::Typed(TypedValue::Boolean(true)))
+        );
+
+        // Without the conversion declared, the field stays a plain string.
+        let parsed = round_trip(&group, &meta, false, &FieldConversions::new());
+        assert_eq!(
+            first_entry(&parsed).fields.get("Enabled"),
+            Some(&Value::Unprotected("True".to_string()))
+        );
+    }
+}